@@ -0,0 +1,275 @@
+//! Circuit breaker around database access.
+//!
+//! Configured with [`CircuitBreakerConfig`]. While the circuit is open,
+//! queries and transactions through [`Database`](crate::Database) fail
+//! immediately with a connection error that [`is_open_error`] recognises, instead of each
+//! waiting for `acquire_timeout`.
+
+use sea_orm::sqlx;
+use sea_orm::{DatabaseConnection, DbErr, RuntimeErr};
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::{fmt, io};
+use tracing::{info, warn};
+
+use crate::config::CircuitBreakerConfig;
+
+/// State of a circuit breaker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CircuitState {
+    /// Queries go through.
+    Closed,
+    /// Queries fail immediately.
+    Open,
+    /// A probe is checking whether the database is back.
+    HalfOpen,
+}
+
+/// Whether an error was returned by an open circuit breaker, rather than
+/// by the database.
+///
+/// `eywa_errors::AppError` has no variant for this, so the error is a
+/// [`DbErr::Conn`] carrying a private marker type, and is handled like any
+/// other connection failure unless singled out with this function.
+///
+/// # Example
+///
+/// ```
+/// use eywa_database::{AppError, circuit};
+///
+/// fn status(e: &AppError) -> u16 {
+///     match e {
+///         AppError::DatabaseError(e) if circuit::is_open_error(e) => 503,
+///         _ => 500,
+///     }
+/// }
+/// ```
+pub fn is_open_error(e: &DbErr) -> bool {
+    let DbErr::Conn(RuntimeErr::SqlxError(sqlx::Error::Io(e))) = e else {
+        return false;
+    };
+    e.get_ref().is_some_and(|e| e.is::<OpenError>())
+}
+
+/// Marks the errors returned while a circuit is open.
+#[derive(Debug)]
+struct OpenError {
+    database: String,
+    pool: String,
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "circuit breaker is open for the {} pool of the {} database",
+            self.pool, self.database
+        )
+    }
+}
+
+impl std::error::Error for OpenError {}
+
+/// Circuit breaker for one pool.
+pub(crate) struct CircuitBreaker {
+    /// Database and pool names, for logging and metrics labels.
+    database: String,
+    name: String,
+    config: CircuitBreakerConfig,
+    probe_timeout: Duration,
+    state: Mutex<Breaker>,
+}
+
+struct Breaker {
+    state: CircuitState,
+    /// Consecutive connection failures.
+    failures: u32,
+    opened_at: Instant,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(
+        database: &str,
+        name: &str,
+        config: CircuitBreakerConfig,
+        probe_timeout: Duration,
+    ) -> Self {
+        Self {
+            database: database.to_string(),
+            name: name.to_string(),
+            config,
+            probe_timeout,
+            state: Mutex::new(Breaker {
+                state: CircuitState::Closed,
+                failures: 0,
+                opened_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub(crate) fn state(&self) -> CircuitState {
+        self.state.lock().unwrap().state
+    }
+
+    /// Let a call through, or fail fast while the circuit is open.
+    ///
+    /// Once the circuit has been open for `reset_timeout`, the first caller
+    /// probes the database and goes ahead if it answers; everyone else
+    /// keeps failing fast until then.
+    pub(crate) async fn admit(&self, conn: &DatabaseConnection) -> Result<(), DbErr> {
+        {
+            let mut breaker = self.state.lock().unwrap();
+            match breaker.state {
+                CircuitState::Closed => return Ok(()),
+                CircuitState::Open if breaker.opened_at.elapsed() >= self.config.reset_timeout => {
+                    breaker.state = CircuitState::HalfOpen;
+                    info!(
+                        "Circuit breaker for the {} pool of the {} database is half-open, probing the database",
+                        self.name, self.database
+                    );
+                }
+                CircuitState::Open | CircuitState::HalfOpen => return Err(self.open_error()),
+            }
+        }
+
+        let mut probe = Probe(Some(self));
+        let result = match tokio::time::timeout(self.probe_timeout, conn.ping()).await {
+            Ok(result) => result,
+            Err(_) => Err(DbErr::Conn(RuntimeErr::Internal(format!(
+                "probe timed out after {:?}",
+                self.probe_timeout
+            )))),
+        };
+        probe.0 = None;
+        match result {
+            Ok(()) => {
+                self.close();
+                Ok(())
+            }
+            Err(e) => {
+                self.open();
+                Err(e)
+            }
+        }
+    }
+
+    /// Count the outcome of a call.
+    pub(crate) fn record<T>(&self, result: &Result<T, DbErr>) {
+        match result {
+            Err(e) if is_open_error(e) => {}
+            Err(DbErr::Conn(_) | DbErr::ConnectionAcquire(_)) => {
+                let mut breaker = self.state.lock().unwrap();
+                breaker.failures += 1;
+                if breaker.state == CircuitState::Closed
+                    && breaker.failures >= self.config.failure_threshold
+                {
+                    drop(breaker);
+                    self.open();
+                }
+            }
+            // Any answer from the database shows that it is reachable.
+            _ => self.close(),
+        }
+    }
+
+    fn open(&self) {
+        let mut breaker = self.state.lock().unwrap();
+        if breaker.state != CircuitState::Open {
+            warn!(
+                "Circuit breaker for the {} pool of the {} database opened after {} consecutive connection failure(s), failing fast for {:?}",
+                self.name, self.database, breaker.failures, self.config.reset_timeout
+            );
+        }
+        breaker.state = CircuitState::Open;
+        breaker.opened_at = Instant::now();
+        #[cfg(feature = "metrics")]
+        self.publish(1.0);
+    }
+
+    fn close(&self) {
+        let mut breaker = self.state.lock().unwrap();
+        breaker.failures = 0;
+        if breaker.state != CircuitState::Closed {
+            breaker.state = CircuitState::Closed;
+            info!(
+                "Circuit breaker for the {} pool of the {} database closed, the database is reachable again",
+                self.name, self.database
+            );
+            #[cfg(feature = "metrics")]
+            self.publish(0.0);
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn publish(&self, open: f64) {
+        metrics::gauge!(
+            "eywa_database_circuit_open",
+            "database" => self.database.clone(),
+            "pool" => self.name.clone()
+        )
+        .set(open);
+    }
+
+    fn open_error(&self) -> DbErr {
+        let e = OpenError {
+            database: self.database.clone(),
+            pool: self.name.clone(),
+        };
+        DbErr::Conn(RuntimeErr::SqlxError(sqlx::Error::Io(io::Error::other(e))))
+    }
+}
+
+/// Reopens the circuit if a probe is cancelled before it finishes.
+struct Probe<'a>(Option<&'a CircuitBreaker>);
+
+impl Drop for Probe<'_> {
+    fn drop(&mut self) {
+        if let Some(breaker) = self.0 {
+            breaker.open();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::tests::unreachable_db;
+
+    #[tokio::test]
+    async fn test_breaker_opens_and_probes() {
+        let config = CircuitBreakerConfig {
+            failure_threshold: 2,
+            reset_timeout: Duration::from_millis(50),
+        };
+        let breaker = CircuitBreaker::new("default", "primary", config, Duration::from_millis(200));
+        let failure = Err::<(), _>(DbErr::ConnectionAcquire(sea_orm::ConnAcquireErr::Timeout));
+
+        breaker.record(&failure);
+        breaker.record(&Err::<(), _>(DbErr::Custom(
+            "not a connection error".into(),
+        )));
+        breaker.record(&failure);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker.record(&failure);
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        // The database can't be reached, so the probe fails and the circuit reopens.
+        let db = unreachable_db();
+        let e = breaker.admit(db.connection()).await.unwrap_err();
+        assert!(is_open_error(&e));
+        assert!(e.to_string().contains("circuit breaker is open"));
+        assert!(!is_open_error(&DbErr::Conn(RuntimeErr::Internal(
+            "circuit breaker is open".into()
+        ))));
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let e = breaker.admit(db.connection()).await.unwrap_err();
+        assert!(!is_open_error(&e));
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        breaker.record(&Ok(()));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.admit(db.connection()).await.is_ok());
+    }
+}
@@ -0,0 +1,299 @@
+//! Following the primary around a high-availability cluster.
+//!
+//! When `hosts` are configured, every new connection checks that its
+//! server accepts writes. If it doesn't, or a query runs into a read-only
+//! server or fails to connect, the hosts are polled for the new primary and
+//! the pool's connect options are switched over to it. Connections opened
+//! before the switch are discarded when they are next acquired.
+
+use sea_orm::sqlx::postgres::{PgConnectOptions, PgConnection, PgPool};
+use sea_orm::sqlx::{self, ConnectOptions as _, Connection as _};
+use sea_orm::{DbErr, RuntimeErr};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::task::AbortHandle;
+use tracing::{info, warn};
+
+use crate::config::{self, ConfigError, DatabaseConfig};
+
+/// SQLSTATE of `read_only_sql_transaction`, returned when writing to a
+/// server in recovery or with `default_transaction_read_only` on.
+const READ_ONLY_SQL_TRANSACTION: &str = "25006";
+
+/// How long to wait between elections while no host accepts writes.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The hosts that may hold the primary, and which of them does.
+pub(crate) struct Failover {
+    /// Connect options for each host, in the configured order.
+    hosts: Vec<PgConnectOptions>,
+    /// Index of the host the pool connects to.
+    current: AtomicUsize,
+    connect_timeout: Duration,
+    /// When the pool last moved to another host.
+    moved_at: Mutex<Option<Instant>>,
+    wake: Notify,
+}
+
+impl Failover {
+    /// The failover state for a configuration, if it lists `hosts`.
+    ///
+    /// Fails if any of the hosts can't be parsed, so that there is always
+    /// a current one.
+    pub(crate) fn new(
+        config: &DatabaseConfig,
+        pg: &PgConnectOptions,
+    ) -> Result<Option<Self>, ConfigError> {
+        if config.hosts.is_empty() {
+            return Ok(None);
+        }
+        let hosts = config
+            .hosts
+            .iter()
+            .map(|host| {
+                let (host, port) =
+                    config::parse_host(host).ok_or_else(|| config::invalid_host(host))?;
+                let pg = pg.clone().host(&host);
+                Ok(match port {
+                    Some(port) => pg.port(port),
+                    None => pg,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Some(Self {
+            hosts,
+            current: AtomicUsize::new(0),
+            connect_timeout: config.connect_timeout,
+            moved_at: Mutex::new(None),
+            wake: Notify::new(),
+        }))
+    }
+
+    /// Connect options for the current primary.
+    pub(crate) fn current(&self) -> PgConnectOptions {
+        self.hosts[self.current.load(Ordering::Relaxed)].clone()
+    }
+
+    /// Look for a new primary in the background. Triggers arriving while a
+    /// look is underway are coalesced into one more.
+    pub(crate) fn trigger(&self) {
+        self.wake.notify_one();
+    }
+
+    /// Look for a new primary if an error suggests it has moved.
+    pub(crate) fn observe(&self, e: &DbErr) {
+        if is_read_only(e) || matches!(e, DbErr::Conn(_) | DbErr::ConnectionAcquire(_)) {
+            self.trigger();
+        }
+    }
+
+    /// Whether a connection of the given age predates the last move.
+    pub(crate) fn is_stale(&self, age: Duration) -> bool {
+        self.moved_at
+            .lock()
+            .unwrap()
+            .is_some_and(|moved_at| age > moved_at.elapsed())
+    }
+
+    /// Fail connections to servers that don't accept writes.
+    pub(crate) async fn check_writable(&self, conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+        if is_writable(conn).await? {
+            Ok(())
+        } else {
+            self.trigger();
+            Err(sqlx::Error::Protocol(
+                "server is read-only, looking for the new primary".to_string(),
+            ))
+        }
+    }
+
+    /// Find the host that accepts writes, trying the current one first.
+    ///
+    /// Returns whether the primary moved. If no host accepts writes, the
+    /// current one is kept.
+    pub(crate) async fn elect(&self) -> bool {
+        let current = self.current.load(Ordering::Relaxed);
+        let order = std::iter::once(current).chain((0..self.hosts.len()).filter(|&i| i != current));
+        for i in order {
+            let host = &self.hosts[i];
+            match tokio::time::timeout(self.connect_timeout, probe(host)).await {
+                Ok(Ok(true)) => {
+                    if i == current {
+                        return false;
+                    }
+                    self.current.store(i, Ordering::Relaxed);
+                    *self.moved_at.lock().unwrap() = Some(Instant::now());
+                    warn!("Primary moved to {}:{}", host.get_host(), host.get_port());
+                    return true;
+                }
+                Ok(Ok(false)) => {}
+                Ok(Err(e)) => info!(
+                    "Could not reach {}:{} while looking for the primary: {}",
+                    host.get_host(),
+                    host.get_port(),
+                    e
+                ),
+                Err(_) => info!(
+                    "Timed out reaching {}:{} while looking for the primary",
+                    host.get_host(),
+                    host.get_port()
+                ),
+            }
+        }
+        warn!("No host accepts writes, staying with the current one");
+        false
+    }
+}
+
+/// Move the pool to the new primary whenever a failover is triggered,
+/// until the pool is closed or the task is aborted.
+pub(crate) fn spawn_monitor(pool: PgPool, failover: std::sync::Arc<Failover>) -> AbortHandle {
+    let task = tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = failover.wake.notified() => {}
+                _ = pool.close_event() => return,
+            }
+            if failover.elect().await {
+                pool.set_connect_options(failover.current());
+            }
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+    });
+    task.abort_handle()
+}
+
+async fn probe(host: &PgConnectOptions) -> Result<bool, sqlx::Error> {
+    let mut conn = host.connect().await?;
+    let writable = is_writable(&mut conn).await;
+    let _ = conn.close().await;
+    writable
+}
+
+async fn is_writable(conn: &mut PgConnection) -> Result<bool, sqlx::Error> {
+    let read_only: String = sqlx::query_scalar("SHOW transaction_read_only")
+        .fetch_one(conn)
+        .await?;
+    Ok(read_only == "off")
+}
+
+/// Whether the server refused a write because it is read-only.
+pub(crate) fn is_read_only(e: &DbErr) -> bool {
+    let (DbErr::Exec(RuntimeErr::SqlxError(e)) | DbErr::Query(RuntimeErr::SqlxError(e))) = e else {
+        return false;
+    };
+    e.as_database_error()
+        .and_then(|e| e.code())
+        .is_some_and(|code| code == READ_ONLY_SQL_TRANSACTION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::error::{DatabaseError, ErrorKind};
+
+    #[test]
+    fn test_hosts_replace_host_and_port() {
+        let config = DatabaseConfig {
+            hosts: vec![
+                "pg-1".to_string(),
+                "pg-2:6432".to_string(),
+                "[::1]:5433".to_string(),
+            ],
+            ..DatabaseConfig::new("postgres://eywa@primary:5555/eywa")
+        };
+        let pg: PgConnectOptions = config.url.parse().unwrap();
+        let failover = Failover::new(&config, &pg).unwrap().unwrap();
+        let hosts: Vec<_> = failover
+            .hosts
+            .iter()
+            .map(|pg| (pg.get_host(), pg.get_port(), pg.get_database()))
+            .collect();
+        assert_eq!(
+            hosts,
+            [
+                ("pg-1", 5555, Some("eywa")),
+                ("pg-2", 6432, Some("eywa")),
+                ("::1", 5433, Some("eywa")),
+            ]
+        );
+        assert_eq!(failover.current().get_host(), "pg-1");
+
+        assert!(!failover.is_stale(Duration::from_secs(3600)));
+        *failover.moved_at.lock().unwrap() = Some(Instant::now() - Duration::from_secs(10));
+        assert!(failover.is_stale(Duration::from_secs(60)));
+        assert!(!failover.is_stale(Duration::from_secs(1)));
+
+        let config = DatabaseConfig::new("postgres://primary/eywa");
+        assert!(Failover::new(&config, &pg).unwrap().is_none());
+
+        // Validation is skipped here, but a bad host still can't leave the
+        // list empty.
+        let config = DatabaseConfig {
+            hosts: vec!["pg-1:port".to_string()],
+            ..config
+        };
+        assert!(matches!(Failover::new(&config, &pg), Err(e) if e.field == "hosts"));
+    }
+
+    #[derive(Debug)]
+    struct ReadOnly;
+
+    impl std::fmt::Display for ReadOnly {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(self.message())
+        }
+    }
+
+    impl std::error::Error for ReadOnly {}
+
+    impl DatabaseError for ReadOnly {
+        fn message(&self) -> &str {
+            "cannot execute INSERT in a read-only transaction"
+        }
+
+        fn code(&self) -> Option<std::borrow::Cow<'_, str>> {
+            Some(READ_ONLY_SQL_TRANSACTION.into())
+        }
+
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    #[test]
+    fn test_read_only_error_triggers_election() {
+        use futures::FutureExt;
+
+        let config = DatabaseConfig {
+            hosts: vec!["pg-1".to_string(), "pg-2".to_string()],
+            ..DatabaseConfig::new("postgres://eywa@primary/eywa")
+        };
+        let pg: PgConnectOptions = config.url.parse().unwrap();
+        let failover = Failover::new(&config, &pg).unwrap().unwrap();
+
+        failover.observe(&DbErr::Custom("not from the server".into()));
+        assert!(failover.wake.notified().now_or_never().is_none());
+
+        let e = DbErr::Exec(RuntimeErr::SqlxError(sqlx::Error::Database(Box::new(
+            ReadOnly,
+        ))));
+        assert!(is_read_only(&e));
+        failover.observe(&e);
+        assert!(failover.wake.notified().now_or_never().is_some());
+    }
+}
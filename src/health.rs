@@ -0,0 +1,149 @@
+//! Database health checks.
+
+use sea_orm::{ConnectionTrait, DatabaseConnection, DbBackend, Statement};
+use serde::{Serialize, Serializer};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::circuit::CircuitState;
+use crate::state::{self, PoolState};
+
+/// Result of a database health check.
+///
+/// Serializes to JSON suitable for a readiness endpoint, with the latency
+/// given in milliseconds:
+///
+/// ```json
+/// {"reachable":true,"latency_ms":1.2,"server_version":"16.2","pool_size":5,"pool_idle":4,"circuit_breaker":"closed","error":null}
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HealthReport {
+    /// Whether the database answered within the timeout.
+    pub reachable: bool,
+
+    /// Round-trip time of the check, including acquiring a connection.
+    #[serde(rename = "latency_ms", serialize_with = "serialize_millis")]
+    pub latency: Option<Duration>,
+
+    /// Server version, e.g. `16.2`.
+    pub server_version: Option<String>,
+
+    /// Connections currently open in the pool.
+    pub pool_size: u32,
+
+    /// Open connections not in use.
+    pub pool_idle: usize,
+
+    /// State of the circuit breaker, if one is configured.
+    pub circuit_breaker: Option<CircuitState>,
+
+    /// Why the check failed, if it did.
+    pub error: Option<String>,
+}
+
+impl HealthReport {
+    /// Whether the database is usable.
+    pub fn is_healthy(&self) -> bool {
+        self.reachable
+    }
+}
+
+/// Ping the database and report its state.
+///
+/// The check bypasses the circuit breaker, and closes it if the database
+/// answers, so that a service taken out of rotation while the circuit was
+/// open can come back without waiting for traffic.
+pub(crate) async fn check(
+    conn: &DatabaseConnection,
+    state: Option<&PoolState>,
+    timeout: Duration,
+) -> HealthReport {
+    let (pool_size, pool_idle) = match state::pool(conn) {
+        Some(pool) => (pool.size(), pool.num_idle()),
+        None => (0, 0),
+    };
+
+    let start = Instant::now();
+    let result = tokio::time::timeout(timeout, server_version(conn)).await;
+    let latency = start.elapsed();
+
+    let breaker = state.and_then(|state| state.breaker.as_ref());
+    if let (Some(breaker), Ok(result)) = (breaker, &result) {
+        breaker.record(result);
+    }
+
+    let (server_version, error) = match result {
+        Ok(Ok(version)) => (Some(version), None),
+        Ok(Err(e)) => (None, Some(e.to_string())),
+        Err(_) => (None, Some(format!("timed out after {timeout:?}"))),
+    };
+    if let Some(error) = &error {
+        warn!("Database health check failed: {}", error);
+    }
+
+    HealthReport {
+        reachable: error.is_none(),
+        latency: error.is_none().then_some(latency),
+        server_version,
+        pool_size,
+        pool_idle,
+        circuit_breaker: breaker.map(|breaker| breaker.state()),
+        error,
+    }
+}
+
+async fn server_version(conn: &DatabaseConnection) -> Result<String, sea_orm::DbErr> {
+    let row = conn
+        .query_one(Statement::from_string(
+            DbBackend::Postgres,
+            "SELECT current_setting('server_version') AS version",
+        ))
+        .await?
+        .ok_or_else(|| sea_orm::DbErr::RecordNotFound("server_version".to_string()))?;
+    row.try_get("", "version")
+}
+
+fn serialize_millis<S: Serializer>(
+    latency: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    latency
+        .map(|latency| latency.as_secs_f64() * 1000.0)
+        .serialize(serializer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::tests::unreachable_db;
+
+    #[tokio::test]
+    async fn test_unreachable_database() {
+        let db = unreachable_db();
+
+        let report = check(db.connection(), None, Duration::from_millis(200)).await;
+        assert!(!report.is_healthy());
+        assert_eq!(report.latency, None);
+        assert_eq!(report.server_version, None);
+        assert_eq!(report.pool_size, 0);
+        assert_eq!(report.circuit_breaker, None);
+        assert!(report.error.is_some());
+    }
+
+    #[test]
+    fn test_report_serializes_latency_as_millis() {
+        let report = HealthReport {
+            reachable: true,
+            latency: Some(Duration::from_micros(1500)),
+            server_version: Some("16.2".to_string()),
+            pool_size: 5,
+            pool_idle: 4,
+            circuit_breaker: Some(CircuitState::Closed),
+            error: None,
+        };
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["latency_ms"], 1.5);
+        assert_eq!(json["server_version"], "16.2");
+        assert_eq!(json["circuit_breaker"], "closed");
+    }
+}
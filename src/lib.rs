@@ -6,7 +6,15 @@
 //!
 //! - Connection pool management with smart defaults
 //! - Configurable pool settings via `DatabaseConfig`
+//! - Read/write splitting across a primary and read replicas, with
+//!   read-your-writes consistency tokens
+//! - Failover to a new primary in high-availability clusters
+//! - An optional circuit breaker that fails fast while the database is down
+//! - A registry for services that talk to several named databases
 //! - Transaction helpers for safe database operations
+//! - Schema-per-tenant transactions, and a pool per tenant for database-per-tenant setups
+//! - Health checks for liveness and readiness endpoints
+//! - Pool statistics, optionally exported through the `metrics` crate
 //! - Seamless integration with Sea-ORM
 //!
 //! ## Quick Start
@@ -28,27 +36,44 @@
 //! ## Using Transactions
 //!
 //! ```no_run
-//! use eywa_database::transaction;
-//! use sea_orm::DatabaseConnection;
+//! use eywa_database::Database;
 //!
-//! # async fn example(db: &DatabaseConnection) -> eywa_database::Result<()> {
-//! transaction::with_transaction(db, |txn| async move {
+//! # async fn example(db: &Database) -> eywa_database::Result<()> {
+//! db.with_transaction(|txn| Box::pin(async move {
 //!     // Your transactional logic here
 //!     Ok(())
-//! }).await?;
+//! })).await?;
 //! # Ok(())
 //! # }
 //! ```
 
+pub mod circuit;
 pub mod config;
+pub mod consistency;
+mod failover;
+pub mod health;
 pub mod pool;
+pub mod registry;
+mod replica;
+mod state;
+pub mod stats;
+pub mod tenant;
 pub mod transaction;
 
 // Re-export commonly used types
-pub use config::DatabaseConfig;
+pub use circuit::CircuitState;
+pub use config::{
+    CircuitBreakerConfig, ConfigError, DatabaseConfig, LogLevel, Profile, RegistryConfig,
+    ReplicaSelection, RetryConfig, SessionConfig, TenantPoolsConfig, TlsConfig, TlsMode,
+};
+pub use consistency::ConsistencyToken;
 pub use eywa_errors::{AppError, Result};
+pub use health::HealthReport;
 pub use pool::Database;
+pub use registry::DatabaseRegistry;
 pub use sea_orm;
+pub use stats::PoolStats;
+pub use tenant::{TenantPools, TenantScope};
 
 // Re-export Sea-ORM types for convenience
 pub use sea_orm::{
@@ -0,0 +1,202 @@
+//! Several named databases in one service.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::pool::HEALTH_CHECK_TIMEOUT;
+use crate::{AppError, Database, HealthReport, RegistryConfig, Result};
+
+/// Databases by name, e.g. `core`, `analytics` and `audit`.
+///
+/// Built from a [`RegistryConfig`], which deserializes from a table per
+/// database. Each database's metrics and log messages are labelled with
+/// its name, unless its configuration sets a `name` of its own.
+///
+/// # Example
+///
+/// ```no_run
+/// use eywa_database::{DatabaseRegistry, RegistryConfig};
+///
+/// #[derive(serde::Deserialize)]
+/// struct AppConfig {
+///     databases: RegistryConfig,
+/// }
+///
+/// # async fn example(config: AppConfig) -> eywa_database::Result<()> {
+/// let registry = DatabaseRegistry::connect(&config.databases).await?;
+/// let analytics = registry.get("analytics").expect("analytics database is configured");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct DatabaseRegistry {
+    databases: BTreeMap<String, Database>,
+}
+
+impl DatabaseRegistry {
+    /// Connect to every database concurrently, with
+    /// [`Database::connect_with_config`].
+    ///
+    /// Fails if any of them can't be connected to; the name of the failing
+    /// database is logged along with the error.
+    pub async fn connect(config: &RegistryConfig) -> Result<Self> {
+        let connecting = config.named().map(|(name, config)| async move {
+            let db = Database::connect_with_config(&config)
+                .await
+                .inspect_err(|e| warn!("Failed to connect to the {} database: {}", name, e))?;
+            Ok::<_, AppError>((name.to_string(), db))
+        });
+        Ok(Self {
+            databases: futures::future::try_join_all(connecting)
+                .await?
+                .into_iter()
+                .collect(),
+        })
+    }
+
+    /// Create a pool for every database without connecting, with
+    /// [`Database::connect_lazy`].
+    pub fn connect_lazy(config: &RegistryConfig) -> Result<Self> {
+        let databases = config
+            .named()
+            .map(|(name, config)| {
+                let db = Database::connect_lazy(&config).inspect_err(|e| {
+                    warn!("Invalid configuration for the {} database: {}", name, e)
+                })?;
+                Ok((name.to_string(), db))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { databases })
+    }
+
+    /// The database with the given name.
+    pub fn get(&self, name: &str) -> Option<&Database> {
+        self.databases.get(name)
+    }
+
+    /// The names of all databases, in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.databases.keys().map(String::as_str)
+    }
+
+    /// All databases with their names, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Database)> {
+        self.databases.iter().map(|(name, db)| (name.as_str(), db))
+    }
+
+    /// Check every database concurrently, with
+    /// [`Database::health_check`].
+    pub async fn health_check(&self) -> RegistryHealth {
+        self.health_check_with_timeout(HEALTH_CHECK_TIMEOUT).await
+    }
+
+    /// Like [`DatabaseRegistry::health_check`], with a custom timeout.
+    pub async fn health_check_with_timeout(&self, timeout: Duration) -> RegistryHealth {
+        let checks = self.databases.iter().map(|(name, db)| async move {
+            (name.clone(), db.health_check_with_timeout(timeout).await)
+        });
+        RegistryHealth {
+            databases: futures::future::join_all(checks)
+                .await
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    /// Shut every database down concurrently, with [`Database::shutdown`].
+    pub async fn shutdown(&self, timeout: Duration) {
+        futures::future::join_all(self.databases.values().map(|db| db.shutdown(timeout))).await;
+    }
+}
+
+/// Health of every database in a [`DatabaseRegistry`].
+///
+/// Serializes to an object with a [`HealthReport`] per database name.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct RegistryHealth {
+    /// Report for each database, by name.
+    pub databases: BTreeMap<String, HealthReport>,
+}
+
+impl RegistryHealth {
+    /// Whether every database is usable.
+    pub fn is_healthy(&self) -> bool {
+        self.databases.values().all(HealthReport::is_healthy)
+    }
+
+    /// Names of the databases that are not usable.
+    pub fn unhealthy(&self) -> impl Iterator<Item = &str> {
+        self.databases
+            .iter()
+            .filter(|(_, report)| !report.is_healthy())
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DatabaseConfig;
+    use crate::pool::tests::{unreachable_config, unreachable_url};
+
+    #[tokio::test]
+    async fn test_registry() {
+        let config = |dbname: &str| DatabaseConfig {
+            url: unreachable_url(dbname),
+            ..unreachable_config()
+        };
+        let configs = RegistryConfig::from(BTreeMap::from([
+            ("core".to_string(), config("core")),
+            ("analytics".to_string(), config("analytics")),
+        ]));
+        let registry = DatabaseRegistry::connect_lazy(&configs).unwrap();
+        assert_eq!(registry.names().collect::<Vec<_>>(), ["analytics", "core"]);
+        assert_eq!(
+            registry.get("analytics").unwrap().config().url,
+            unreachable_url("analytics")
+        );
+        assert_eq!(registry.get("core").unwrap().stats().database, "core");
+        assert!(registry.get("audit").is_none());
+
+        let health = registry
+            .health_check_with_timeout(Duration::from_millis(200))
+            .await;
+        assert!(!health.is_healthy());
+        assert_eq!(
+            health.unhealthy().collect::<Vec<_>>(),
+            ["analytics", "core"]
+        );
+        let json = serde_json::to_value(&health).unwrap();
+        assert_eq!(json["core"]["reachable"], false);
+
+        registry.shutdown(Duration::from_secs(1)).await;
+        let core = registry.get("core").unwrap();
+        assert!(core.connection().ping().await.is_err());
+
+        let mut configs = configs;
+        configs.databases.get_mut("core").unwrap().max_connections = 0;
+        assert!(DatabaseRegistry::connect(&configs).await.is_err());
+
+        let configs: RegistryConfig = serde_json::from_str(
+            r#"{
+                "core": { "url": "postgres://core-db/core" },
+                "audit": { "url": "postgres://audit-db/audit", "name": "audit-log" }
+            }"#,
+        )
+        .unwrap();
+        let names: Vec<_> = configs
+            .named()
+            .map(|(key, config)| (key, config.name.unwrap()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("audit", "audit-log".to_string()),
+                ("core", "core".to_string())
+            ]
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! Read replica selection and health monitoring.
+
+use sea_orm::sqlx::postgres::PgPool;
+use sea_orm::{ConnectionTrait, DatabaseConnection, DbBackend, DbErr, Statement};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::config::{DatabaseConfig, ReplicaSelection};
+use crate::consistency::{self, ConsistencyToken};
+use crate::pool::Node;
+
+/// The configuration for a replica's pool: the primary's, with the URL swapped.
+pub(crate) fn config(primary: &DatabaseConfig, url: &str) -> DatabaseConfig {
+    DatabaseConfig {
+        url: url.to_string(),
+        url_file: None,
+        host: None,
+        hosts: Vec::new(),
+        replicas: Vec::new(),
+        ..primary.clone()
+    }
+}
+
+/// Pick an available replica, or `None` if there is none.
+pub(crate) fn select<'a>(
+    replicas: &'a [Arc<Node>],
+    selection: ReplicaSelection,
+    next: &AtomicUsize,
+) -> Option<&'a Arc<Node>> {
+    candidates(replicas, selection, next).into_iter().next()
+}
+
+/// The available replicas, in order of preference.
+fn candidates<'a>(
+    replicas: &'a [Arc<Node>],
+    selection: ReplicaSelection,
+    next: &AtomicUsize,
+) -> Vec<&'a Arc<Node>> {
+    let available = |node: &&Arc<Node>| node.is_available();
+    match selection {
+        ReplicaSelection::RoundRobin => {
+            let start = next.fetch_add(1, Ordering::Relaxed);
+            (0..replicas.len())
+                .map(|i| &replicas[(start + i) % replicas.len()])
+                .filter(available)
+                .collect()
+        }
+        ReplicaSelection::LeastConnections => {
+            let mut nodes: Vec<_> = replicas.iter().filter(available).collect();
+            nodes.sort_by_key(|node| in_use(&node.pool));
+            nodes
+        }
+    }
+}
+
+/// Wait for an available replica to replay up to `token`, giving up after
+/// `timeout`.
+///
+/// Replicas are asked in order of preference, and asked again with growing
+/// pauses until one has caught up.
+pub(crate) async fn wait_for<'a>(
+    replicas: &'a [Arc<Node>],
+    selection: ReplicaSelection,
+    next: &AtomicUsize,
+    token: ConsistencyToken,
+    timeout: Duration,
+) -> Option<&'a Arc<Node>> {
+    const MAX_PAUSE: Duration = Duration::from_millis(100);
+
+    let deadline = Instant::now() + timeout;
+    let mut pause = Duration::from_millis(5);
+    loop {
+        for node in candidates(replicas, selection, next) {
+            let replayed = consistency::replayed(&node.conn, token);
+            if let Ok(Ok(true)) = tokio::time::timeout_at(deadline, replayed).await {
+                return Some(node);
+            }
+        }
+        if Instant::now() + pause >= deadline {
+            return None;
+        }
+        tokio::time::sleep(pause).await;
+        pause = (pause * 2).min(MAX_PAUSE);
+    }
+}
+
+fn in_use(pool: &PgPool) -> usize {
+    (pool.size() as usize).saturating_sub(pool.num_idle())
+}
+
+/// Replay lag of a replica, or zero once it has replayed everything it
+/// received. `NULL` if the server isn't replaying anything, e.g. because it
+/// is not a replica.
+const REPLAY_LAG: &str = "SELECT CASE \
+    WHEN pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() THEN 0 \
+    ELSE EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp()) \
+    END::float8 AS lag";
+
+/// Check the replicas every `interval` until their pools are dropped or closed.
+pub(crate) fn spawn_monitor(replicas: &[Arc<Node>], interval: Duration, max_lag: Option<Duration>) {
+    let replicas: Vec<Weak<Node>> = replicas.iter().map(Arc::downgrade).collect();
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            for replica in &replicas {
+                let Some(node) = replica.upgrade().filter(|node| !node.pool.is_closed()) else {
+                    return;
+                };
+                check(&node, interval, max_lag).await;
+            }
+        }
+    });
+}
+
+async fn check(node: &Node, timeout: Duration, max_lag: Option<Duration>) {
+    let lag = match tokio::time::timeout(timeout, replay_lag(&node.conn)).await {
+        Ok(Ok(lag)) => Some(lag),
+        _ => None,
+    };
+
+    let up = lag.is_some();
+    let was_up = node.healthy.swap(up, Ordering::Relaxed);
+    if up && !was_up {
+        info!("Read replica at {} is back up", node.url);
+    } else if !up && was_up {
+        warn!("Read replica at {} is down", node.url);
+    }
+
+    let Some(lag) = lag else { return };
+    *node.lag.lock().unwrap() = lag;
+    let lagging = max_lag.zip(lag).is_some_and(|(max, lag)| lag > max);
+    let was_lagging = node.lagging.swap(lagging, Ordering::Relaxed);
+    if lagging && !was_lagging {
+        warn!(
+            "Read replica at {} lags {:?} behind, not reading from it",
+            node.url,
+            lag.unwrap_or_default()
+        );
+    } else if !lagging && was_lagging {
+        info!("Read replica at {} has caught up", node.url);
+    }
+}
+
+async fn replay_lag(conn: &DatabaseConnection) -> Result<Option<Duration>, DbErr> {
+    let row = conn
+        .query_one(Statement::from_string(DbBackend::Postgres, REPLAY_LAG))
+        .await?;
+    let lag: Option<f64> = match row {
+        Some(row) => row.try_get("", "lag")?,
+        None => None,
+    };
+    Ok(lag.map(|secs| Duration::from_secs_f64(secs.max(0.0))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replica_config_swaps_url() {
+        let primary = DatabaseConfig {
+            host: Some("primary".to_string()),
+            replicas: vec!["postgres://replica/eywa".to_string()],
+            max_connections: 7,
+            ..Default::default()
+        };
+        let replica = config(&primary, &primary.replicas[0]);
+        assert_eq!(replica.connection_url().unwrap(), "postgres://replica/eywa");
+        assert!(replica.replicas.is_empty());
+        assert_eq!(replica.max_connections, 7);
+    }
+}
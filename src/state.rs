@@ -0,0 +1,152 @@
+//! Bookkeeping for the pools created by this crate.
+//!
+//! Each `Node` owns the state of its pool and passes it to the helpers it
+//! calls. A plain `DatabaseConnection` carries no state, so work done
+//! through one directly is neither counted nor guarded by the circuit
+//! breaker.
+
+use sea_orm::sqlx::postgres::PgPool;
+use sea_orm::{
+    ConnectionTrait, DatabaseConnection, DatabaseTransaction, DbBackend, DbErr, Statement,
+    TransactionTrait,
+};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use crate::circuit::CircuitBreaker;
+use crate::failover::Failover;
+use crate::stats::PoolMetrics;
+
+/// State shared by everything using one pool.
+pub(crate) struct PoolState {
+    pub(crate) metrics: PoolMetrics,
+    /// Set when the pool follows a moving primary.
+    pub(crate) failover: Option<Arc<Failover>>,
+    pub(crate) breaker: Option<CircuitBreaker>,
+    /// Server process IDs of the transactions in flight.
+    in_flight: Mutex<HashSet<i32>>,
+}
+
+impl PoolState {
+    /// Create the state for a new pool, with the database and pool names
+    /// for metrics labels.
+    pub(crate) fn new(
+        database: &str,
+        name: &str,
+        failover: Option<Arc<Failover>>,
+        breaker: Option<CircuitBreaker>,
+    ) -> Self {
+        Self {
+            metrics: PoolMetrics::new(database, name),
+            failover,
+            breaker,
+            in_flight: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Transactions started through the `Database` handle that haven't
+    /// finished yet.
+    pub(crate) fn in_flight(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+
+    /// Server process IDs running the transactions in flight.
+    pub(crate) fn in_flight_backends(&self) -> Vec<i32> {
+        self.in_flight.lock().unwrap().iter().copied().collect()
+    }
+
+    /// Make a database call through the circuit breaker, if there is one.
+    pub(crate) async fn call<T>(
+        &self,
+        conn: &DatabaseConnection,
+        fut: impl Future<Output = Result<T, DbErr>>,
+    ) -> Result<T, DbErr> {
+        let Some(breaker) = &self.breaker else {
+            return fut.await;
+        };
+        breaker.admit(conn).await?;
+        let result = fut.await;
+        breaker.record(&result);
+        result
+    }
+}
+
+/// Marks a transaction as in flight for as long as it is alive.
+pub(crate) struct InFlight {
+    state: Arc<PoolState>,
+    pid: i32,
+}
+
+impl InFlight {
+    /// Mark a transaction as in flight, looking up the server process
+    /// running it so that shutdown can cut it off.
+    pub(crate) async fn new(
+        state: Arc<PoolState>,
+        txn: &DatabaseTransaction,
+    ) -> Result<Self, DbErr> {
+        let pid = backend_pid(txn).await?;
+        state.in_flight.lock().unwrap().insert(pid);
+        Ok(Self { state, pid })
+    }
+
+    /// Let the pool react to an error the transaction ran into.
+    pub(crate) fn observe(&self, e: &DbErr) {
+        observe(&self.state, e);
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.state.in_flight.lock().unwrap().remove(&self.pid);
+    }
+}
+
+/// The server process ID of a transaction's connection.
+async fn backend_pid(txn: &DatabaseTransaction) -> Result<i32, DbErr> {
+    let row = txn
+        .query_one(Statement::from_string(
+            DbBackend::Postgres,
+            "SELECT pg_backend_pid() AS pid",
+        ))
+        .await?
+        .ok_or_else(|| DbErr::RecordNotFound("pg_backend_pid".to_string()))?;
+    row.try_get("", "pid")
+}
+
+/// Begin a transaction, keeping track of it in the pool's state if given.
+///
+/// The returned guard must be held until the transaction is finished.
+pub(crate) async fn begin(
+    db: &DatabaseConnection,
+    state: Option<&Arc<PoolState>>,
+) -> Result<(DatabaseTransaction, Option<InFlight>), DbErr> {
+    match state {
+        Some(state) => {
+            let txn = state.call(db, state.metrics.acquire(db.begin())).await;
+            let txn = txn.inspect_err(|e| observe(state, e))?;
+            let in_flight = InFlight::new(state.clone(), &txn).await;
+            let in_flight = in_flight.inspect_err(|e| observe(state, e))?;
+            Ok((txn, Some(in_flight)))
+        }
+        None => Ok((db.begin().await?, None)),
+    }
+}
+
+/// Let the pool react to an error it ran into: count acquire timeouts, and
+/// look for a new primary if the current one may have moved.
+pub(crate) fn observe(state: &PoolState, e: &DbErr) {
+    state.metrics.observe(e);
+    if let Some(failover) = &state.failover {
+        failover.observe(e);
+    }
+}
+
+/// The Postgres pool behind a connection.
+pub(crate) fn pool(conn: &DatabaseConnection) -> Option<&PgPool> {
+    match conn {
+        DatabaseConnection::SqlxPostgresPoolConnection(_) => {
+            Some(conn.get_postgres_connection_pool())
+        }
+        _ => None,
+    }
+}
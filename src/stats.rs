@@ -0,0 +1,298 @@
+//! Connection pool statistics.
+//!
+//! Pool size and idle counts come straight from the pool. Acquire latency
+//! and waiters are measured whenever this crate obtains a connection, i.e.
+//! when a transaction is started through [`Database`](crate::Database).
+//! Acquire timeouts are counted for those, and for every query run through
+//! `Database`.
+//!
+//! With the `metrics` feature enabled, the same numbers are published
+//! through the [`metrics`](https://docs.rs/metrics) facade, labelled with
+//! the database's [`name`](crate::DatabaseConfig::name) (`database="core"`,
+//! or `"default"` when unnamed) and the pool's role (`pool="primary"` or
+//! `pool="replica-1"`, `"replica-2"` and so on):
+//!
+//! | Metric | Type |
+//! |---|---|
+//! | `eywa_database_pool_size` | gauge |
+//! | `eywa_database_pool_idle` | gauge |
+//! | `eywa_database_pool_in_use` | gauge |
+//! | `eywa_database_pool_waiters` | gauge |
+//! | `eywa_database_pool_acquire_seconds` | histogram |
+//! | `eywa_database_pool_acquire_timeouts_total` | counter |
+//! | `eywa_database_circuit_open` | gauge, 1 while the circuit breaker is open |
+
+use sea_orm::sqlx::postgres::PgPool;
+use sea_orm::{ConnAcquireErr, DbErr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the acquire latency histogram buckets.
+const BUCKETS: [Duration; 10] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+];
+
+/// Snapshot of a connection pool.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Which database this is: its `name`, or `default` if unnamed.
+    pub database: String,
+
+    /// Which pool of the database this is: `primary`, `replica-1`,
+    /// `replica-2`, ...
+    pub pool: String,
+
+    /// How far a replica lags behind the primary, as of its last check.
+    /// `None` for the primary, or if it hasn't been measured yet.
+    pub replication_lag: Option<Duration>,
+
+    /// Open connections.
+    pub size: u32,
+
+    /// Open connections not in use.
+    pub idle: usize,
+
+    /// Connections currently handed out.
+    pub in_use: usize,
+
+    /// Tasks waiting for a connection.
+    pub waiters: usize,
+
+    /// Time taken to obtain a connection.
+    pub acquire_latency: LatencyHistogram,
+
+    /// Attempts to obtain a connection that ran into `acquire_timeout`.
+    pub acquire_timeouts: u64,
+}
+
+/// Cumulative latency histogram, in the style of Prometheus.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// Observations at or below each bound, in increasing order.
+    pub buckets: Vec<HistogramBucket>,
+
+    /// Total number of observations.
+    pub count: u64,
+
+    /// Sum of all observations.
+    pub sum: Duration,
+}
+
+impl LatencyHistogram {
+    /// Average latency, if anything was observed.
+    pub fn mean(&self) -> Option<Duration> {
+        let count = u32::try_from(self.count).ok().filter(|&count| count > 0)?;
+        Some(self.sum / count)
+    }
+}
+
+/// One bucket of a [`LatencyHistogram`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramBucket {
+    /// Upper bound of the bucket.
+    pub le: Duration,
+
+    /// Observations at or below `le`.
+    pub count: u64,
+}
+
+/// Acquire measurements for one pool.
+#[derive(Default)]
+pub(crate) struct PoolMetrics {
+    /// Value of the `database` label.
+    #[cfg(feature = "metrics")]
+    database: String,
+    /// Value of the `pool` label.
+    #[cfg(feature = "metrics")]
+    pool: String,
+    waiters: AtomicUsize,
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+    timeouts: AtomicU64,
+}
+
+impl PoolMetrics {
+    pub(crate) fn new(database: &str, pool: &str) -> Self {
+        #[cfg(not(feature = "metrics"))]
+        let _ = (database, pool);
+        Self {
+            #[cfg(feature = "metrics")]
+            database: database.to_string(),
+            #[cfg(feature = "metrics")]
+            pool: pool.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Run a future that obtains a connection, measuring how long it takes.
+    pub(crate) async fn acquire<T>(
+        &self,
+        fut: impl Future<Output = Result<T, DbErr>>,
+    ) -> Result<T, DbErr> {
+        let _waiting = Waiting::new(&self.waiters);
+        let start = Instant::now();
+        let result = fut.await;
+        if result.is_ok() {
+            self.record(start.elapsed());
+        }
+        result
+    }
+
+    /// Count an error if it is an acquire timeout.
+    pub(crate) fn observe(&self, e: &DbErr) {
+        if let DbErr::ConnectionAcquire(ConnAcquireErr::Timeout) = e {
+            self.timeouts.fetch_add(1, Ordering::Relaxed);
+            #[cfg(feature = "metrics")]
+            metrics::counter!("eywa_database_pool_acquire_timeouts_total", &self.labels())
+                .increment(1);
+        }
+    }
+
+    fn record(&self, latency: Duration) {
+        if let Some(i) = BUCKETS.iter().position(|&le| latency <= le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(
+            u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+        #[cfg(feature = "metrics")]
+        metrics::histogram!("eywa_database_pool_acquire_seconds", &self.labels())
+            .record(latency.as_secs_f64());
+    }
+
+    #[cfg(feature = "metrics")]
+    fn labels(&self) -> [(&'static str, String); 2] {
+        [
+            ("database", self.database.clone()),
+            ("pool", self.pool.clone()),
+        ]
+    }
+
+    pub(crate) fn snapshot(&self, pool: &PgPool) -> PoolStats {
+        let size = pool.size();
+        let idle = pool.num_idle();
+
+        let mut cumulative = 0;
+        let buckets = BUCKETS
+            .iter()
+            .zip(&self.buckets)
+            .map(|(&le, count)| {
+                cumulative += count.load(Ordering::Relaxed);
+                HistogramBucket {
+                    le,
+                    count: cumulative,
+                }
+            })
+            .collect();
+
+        PoolStats {
+            database: String::new(),
+            pool: String::new(),
+            replication_lag: None,
+            size,
+            idle,
+            in_use: (size as usize).saturating_sub(idle),
+            waiters: self.waiters.load(Ordering::Relaxed),
+            acquire_latency: LatencyHistogram {
+                buckets,
+                count: self.count.load(Ordering::Relaxed),
+                sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)),
+            },
+            acquire_timeouts: self.timeouts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counts a task as waiting for as long as it is alive.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    fn new(waiters: &'a AtomicUsize) -> Self {
+        waiters.fetch_add(1, Ordering::Relaxed);
+        Self(waiters)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Keep the pool gauges up to date until the pool is closed or the task is
+/// aborted.
+#[cfg(feature = "metrics")]
+pub(crate) fn spawn_publisher(
+    pool: PgPool,
+    state: std::sync::Arc<crate::state::PoolState>,
+) -> tokio::task::AbortHandle {
+    const INTERVAL: Duration = Duration::from_secs(5);
+
+    let task = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(INTERVAL);
+        while !pool.is_closed() {
+            interval.tick().await;
+            publish(&state.metrics, &state.metrics.snapshot(&pool));
+        }
+    });
+    task.abort_handle()
+}
+
+#[cfg(feature = "metrics")]
+fn publish(metrics: &PoolMetrics, stats: &PoolStats) {
+    let label = |name| metrics::gauge!(name, &metrics.labels());
+    label("eywa_database_pool_size").set(f64::from(stats.size));
+    label("eywa_database_pool_idle").set(stats.idle as f64);
+    label("eywa_database_pool_in_use").set(stats.in_use as f64);
+    label("eywa_database_pool_waiters").set(stats.waiters as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::tests::unreachable_db;
+    use crate::state;
+
+    #[tokio::test]
+    async fn test_acquire_records_latency_and_timeouts() {
+        let metrics = PoolMetrics::new("default", "primary");
+        metrics.acquire(async { Ok::<_, DbErr>(()) }).await.unwrap();
+        let timeout = metrics
+            .acquire(async { Err::<(), _>(DbErr::ConnectionAcquire(ConnAcquireErr::Timeout)) })
+            .await;
+        metrics.observe(&timeout.unwrap_err());
+        metrics.observe(&DbErr::ConnectionAcquire(ConnAcquireErr::ConnectionClosed));
+
+        let db = unreachable_db();
+        let stats = metrics.snapshot(state::pool(db.connection()).unwrap());
+        assert_eq!(stats.size, 0);
+        assert_eq!(stats.waiters, 0);
+        assert_eq!(stats.acquire_timeouts, 1);
+        assert_eq!(stats.acquire_latency.count, 1);
+        assert_eq!(stats.acquire_latency.buckets.len(), BUCKETS.len());
+        assert_eq!(stats.acquire_latency.buckets.last().unwrap().count, 1);
+    }
+
+    #[test]
+    fn test_histogram_mean() {
+        let histogram = LatencyHistogram {
+            buckets: Vec::new(),
+            count: 4,
+            sum: Duration::from_millis(10),
+        };
+        assert_eq!(histogram.mean(), Some(Duration::from_micros(2500)));
+        assert_eq!(LatencyHistogram::default().mean(), None);
+    }
+}
@@ -1,15 +1,25 @@
 //! Transaction management helpers.
 
-use sea_orm::{DatabaseConnection, TransactionTrait};
+use sea_orm::{DatabaseConnection, DatabaseTransaction, DbErr};
+use std::sync::Arc;
 use tracing::{debug, warn};
 
 use crate::Result;
+use crate::consistency::{self, ConsistencyToken};
+use crate::state::{self, PoolState};
+use crate::tenant;
 
 /// Execute a function within a database transaction.
 ///
 /// The transaction is automatically committed if the function returns `Ok`,
 /// and rolled back if it returns `Err`.
 ///
+/// On a plain connection, the transaction is not counted in the pool
+/// statistics or by [`Database::shutdown`](crate::Database::shutdown), and
+/// bypasses the circuit breaker and failover. Use
+/// [`Database::with_transaction`](crate::Database::with_transaction) to
+/// get those.
+///
 /// # Example
 ///
 /// ```no_run
@@ -17,15 +27,33 @@ use crate::Result;
 /// use sea_orm::DatabaseConnection;
 ///
 /// # async fn example(db: &DatabaseConnection) -> eywa_database::Result<()> {
-/// transaction::with_transaction(db, |txn| async move {
+/// transaction::with_transaction(db, |txn| Box::pin(async move {
 ///     // Your transactional logic here
 ///     // All database operations use `txn` instead of `db`
 ///     Ok(())
-/// }).await?;
+/// })).await?;
 /// # Ok(())
 /// # }
 /// ```
 pub async fn with_transaction<F, R>(db: &DatabaseConnection, f: F) -> Result<R>
+where
+    F: for<'txn> FnOnce(
+        &'txn sea_orm::DatabaseTransaction,
+    ) -> std::pin::Pin<Box<dyn futures::Future<Output = Result<R>> + Send + 'txn>>
+        + Send,
+    R: Send,
+{
+    with_transaction_in(db, None, None, f).await
+}
+
+/// [`with_transaction`], keeping track of the transaction in the pool's
+/// state and setting `search_path` for it if given.
+pub(crate) async fn with_transaction_in<F, R>(
+    db: &DatabaseConnection,
+    state: Option<&Arc<PoolState>>,
+    search_path: Option<&str>,
+    f: F,
+) -> Result<R>
 where
     F: for<'txn> FnOnce(
         &'txn sea_orm::DatabaseTransaction,
@@ -35,24 +63,27 @@ where
 {
     debug!("Starting transaction");
 
-    let txn = db
-        .begin()
+    let (txn, in_flight) = begin(db, state, search_path)
         .await
-        .map_err(|e| eywa_errors::AppError::DatabaseError(e))?;
+        .map_err(eywa_errors::AppError::DatabaseError)?;
 
     match f(&txn).await {
         Ok(result) => {
             txn.commit()
                 .await
-                .map_err(|e| eywa_errors::AppError::DatabaseError(e))?;
+                .inspect_err(|e| observe(&in_flight, e))
+                .map_err(eywa_errors::AppError::DatabaseError)?;
             debug!("Transaction committed successfully");
             Ok(result)
         }
         Err(e) => {
+            if let eywa_errors::AppError::DatabaseError(db_err) = &e {
+                observe(&in_flight, db_err);
+            }
             if let Err(commit_err) = txn
                 .rollback()
                 .await
-                .map_err(|err| eywa_errors::AppError::DatabaseError(err))
+                .map_err(eywa_errors::AppError::DatabaseError)
             {
                 warn!("Failed to rollback transaction: {}", commit_err);
             } else {
@@ -63,10 +94,70 @@ where
     }
 }
 
+/// Execute a function within a database transaction, and capture a
+/// [`ConsistencyToken`] once it has committed.
+///
+/// Reads routed through
+/// [`Database::reader_after`](crate::Database::reader_after) with the
+/// token are guaranteed to see the transaction's writes. Capturing the
+/// token costs one extra query against the primary after the commit.
+///
+/// # Example
+///
+/// ```no_run
+/// use eywa_database::transaction;
+/// use sea_orm::DatabaseConnection;
+///
+/// # async fn example(db: &DatabaseConnection) -> eywa_database::Result<()> {
+/// let ((), token) = transaction::with_transaction_token(db, |txn| Box::pin(async move {
+///     // Your transactional logic here
+///     Ok(())
+/// })).await?;
+/// // Hand `token.to_string()` to the client, or keep it for this request.
+/// # Ok(())
+/// # }
+/// ```
+pub async fn with_transaction_token<F, R>(
+    db: &DatabaseConnection,
+    f: F,
+) -> Result<(R, ConsistencyToken)>
+where
+    F: for<'txn> FnOnce(
+        &'txn sea_orm::DatabaseTransaction,
+    ) -> std::pin::Pin<Box<dyn futures::Future<Output = Result<R>> + Send + 'txn>>
+        + Send,
+    R: Send,
+{
+    with_transaction_token_in(db, None, f).await
+}
+
+/// [`with_transaction_token`], keeping track of the transaction in the
+/// pool's state if given.
+pub(crate) async fn with_transaction_token_in<F, R>(
+    db: &DatabaseConnection,
+    state: Option<&Arc<PoolState>>,
+    f: F,
+) -> Result<(R, ConsistencyToken)>
+where
+    F: for<'txn> FnOnce(
+        &'txn sea_orm::DatabaseTransaction,
+    ) -> std::pin::Pin<Box<dyn futures::Future<Output = Result<R>> + Send + 'txn>>
+        + Send,
+    R: Send,
+{
+    let result = with_transaction_in(db, state, None, f).await?;
+    let token = consistency::current(db)
+        .await
+        .map_err(eywa_errors::AppError::DatabaseError)?;
+    debug!("Transaction committed at {}", token);
+    Ok((result, token))
+}
+
 /// Execute a function within a database transaction, returning a specific error type.
 ///
 /// This is useful when you want to preserve your custom error type through
-/// the transaction boundary.
+/// the transaction boundary. As with [`with_transaction`], a transaction on
+/// a plain connection gets none of the pool's bookkeeping.
 ///
 /// # Example
 ///
@@ -80,11 +171,11 @@ where
 /// #     fn from(e: eywa_errors::AppError) -> Self { MyError::Bar }
 /// # }
 /// # async fn example(db: &DatabaseConnection) -> Result<(), MyError> {
-/// transaction::with_transaction_custom_err(db, |txn| async move {
+/// transaction::with_transaction_custom_err(db, |txn| Box::pin(async move {
 ///     // Your transactional logic here
 ///     // Return Result<T, MyError>
-///     Ok(())
-/// }).await?;
+///     Ok::<_, MyError>(())
+/// })).await?;
 /// # Ok(())
 /// # }
 /// ```
@@ -92,6 +183,25 @@ pub async fn with_transaction_custom_err<F, R, E>(
     db: &DatabaseConnection,
     f: F,
 ) -> std::result::Result<R, E>
+where
+    F: for<'txn> FnOnce(
+        &'txn sea_orm::DatabaseTransaction,
+    ) -> std::pin::Pin<Box<dyn futures::Future<Output = std::result::Result<R, E>> + Send + 'txn>>
+        + Send,
+    R: Send,
+    E: From<eywa_errors::AppError> + Send,
+{
+    with_transaction_custom_err_in(db, None, None, f).await
+}
+
+/// [`with_transaction_custom_err`], keeping track of the transaction in the
+/// pool's state and setting `search_path` for it if given.
+pub(crate) async fn with_transaction_custom_err_in<F, R, E>(
+    db: &DatabaseConnection,
+    state: Option<&Arc<PoolState>>,
+    search_path: Option<&str>,
+    f: F,
+) -> std::result::Result<R, E>
 where
     F: for<'txn> FnOnce(
         &'txn sea_orm::DatabaseTransaction,
@@ -102,19 +212,22 @@ where
 {
     debug!("Starting transaction");
 
-    let txn = db.begin().await.map_err(|e| {
-        eywa_errors::AppError::DatabaseError(e).into()
-    })?;
+    let (txn, in_flight) = begin(db, state, search_path)
+        .await
+        .map_err(eywa_errors::AppError::DatabaseError)?;
 
     match f(&txn).await {
         Ok(result) => {
-            txn.commit().await.map_err(|e| {
-                eywa_errors::AppError::DatabaseError(e).into()
-            })?;
+            txn.commit()
+                .await
+                .inspect_err(|e| observe(&in_flight, e))
+                .map_err(eywa_errors::AppError::DatabaseError)?;
             debug!("Transaction committed successfully");
             Ok(result)
         }
         Err(e) => {
+            // `E` can't be looked into, so only errors from beginning and
+            // committing the transaction reach the pool.
             if let Err(commit_err) = txn.rollback().await {
                 warn!("Failed to rollback transaction: {}", commit_err);
             } else {
@@ -125,8 +238,31 @@ where
     }
 }
 
+/// Let the pool react to a database error that ended a transaction, e.g. a
+/// write refused by a primary that has just been demoted.
+pub(crate) fn observe(in_flight: &Option<state::InFlight>, e: &DbErr) {
+    if let Some(in_flight) = in_flight {
+        in_flight.observe(e);
+    }
+}
+
+/// Begin a transaction through the pool's bookkeeping if given, and scope
+/// it to a `search_path` if given.
+pub(crate) async fn begin(
+    db: &DatabaseConnection,
+    state: Option<&Arc<PoolState>>,
+    search_path: Option<&str>,
+) -> std::result::Result<(DatabaseTransaction, Option<state::InFlight>), DbErr> {
+    let (txn, in_flight) = state::begin(db, state).await?;
+    if let Some(search_path) = search_path {
+        tenant::set_search_path(&txn, search_path).await?;
+    }
+    Ok((txn, in_flight))
+}
+
 #[cfg(test)]
 mod tests {
+    #[allow(unused_imports)]
     use super::*;
 
     #[test]